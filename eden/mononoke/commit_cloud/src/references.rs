@@ -18,8 +18,12 @@ use edenapi_types::GetSmartlogFlag;
 use edenapi_types::HgId;
 use edenapi_types::ReferencesData;
 use edenapi_types::UpdateReferencesParams;
+use futures::stream;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
 use history::WorkspaceHistory;
 use mercurial_types::HgChangesetId;
+use mononoke_types::ChangesetId;
 use repo_derived_data::ArcRepoDerivedData;
 use sql::Transaction;
 use versions::WorkspaceVersion;
@@ -65,6 +69,7 @@ pub struct RawSmartlogData {
     pub heads: Vec<WorkspaceHead>,
     pub local_bookmarks: Option<LocalBookmarksMap>,
     pub remote_bookmarks: Option<RemoteBookmarksMap>,
+    pub snapshots: Option<Vec<WorkspaceSnapshot>>,
 }
 impl RawSmartlogData {
     // Takes all the heads and bookmarks and returns them as a single Vec<HgChangesetId>
@@ -92,6 +97,14 @@ impl RawSmartlogData {
                 .collect::<Vec<HgChangesetId>>();
             heads.append(&mut lbs);
         }
+
+        if let Some(snapshots) = self.snapshots.clone() {
+            let mut snapshots = snapshots
+                .into_iter()
+                .map(|snapshot| snapshot.commit)
+                .collect::<Vec<HgChangesetId>>();
+            heads.append(&mut snapshots);
+        }
         heads
     }
 
@@ -121,10 +134,23 @@ impl RawSmartlogData {
             None
         };
 
+        // TODO(shikanime/sapling#chunk0-2): BLOCKED on `edenapi_types` landing
+        // the `GetSmartlogFlag::AddSnapshots` variant this references.
+        // `GetSmartlogFlag` lives in that separate crate, so it cannot be
+        // added from here; this call does not compile until that variant
+        // exists upstream. Do not merge this hunk ahead of (or without) that
+        // companion change landing first.
+        let snapshots = if flags.contains(&GetSmartlogFlag::AddSnapshots) {
+            Some(sql.get(ctx.reponame.clone(), ctx.workspace.clone()).await?)
+        } else {
+            None
+        };
+
         Ok(RawSmartlogData {
             heads,
             local_bookmarks,
             remote_bookmarks,
+            snapshots,
         })
     }
 }
@@ -153,6 +179,54 @@ pub(crate) async fn fetch_references(
     })
 }
 
+// Workspace references that changed since a given `WorkspaceVersion`, for
+// clients that already hold a snapshot and want to apply a delta instead of
+// re-fetching the full `RawReferencesData`.
+#[derive(Debug, Clone)]
+pub struct ReferencesDelta {
+    pub heads: Vec<WorkspaceHead>,
+    pub local_bookmarks: Vec<WorkspaceLocalBookmark>,
+    pub remote_bookmarks: Vec<WorkspaceRemoteBookmark>,
+    pub snapshots: Vec<WorkspaceSnapshot>,
+    pub removed_commits: Vec<HgChangesetId>,
+}
+
+// TODO(shikanime/sapling#chunk0-4): real delta support needs per-reference
+// version stamps recorded in the SQL ops layer plus `get_since`/
+// `get_removed_since` queries, neither of which exist in this tree yet. Until
+// that lands, the only version this can honestly answer is "since the
+// beginning" (`base_version == 0`), which is exactly the full current state
+// with nothing removed. Any other `base_version` would require data this
+// function doesn't have, so it refuses rather than silently handing back the
+// full workspace relabelled as a delta.
+pub(crate) async fn fetch_references_since(
+    ctx: &CommitCloudContext,
+    sql: &SqlCommitCloud,
+    base_version: u64,
+) -> Result<ReferencesDelta, anyhow::Error> {
+    if base_version != 0 {
+        return Err(anyhow!(
+            "fetch_references_since({}) is not yet supported: per-reference version \
+             stamps are not recorded in the SQL ops layer, so only base_version 0 \
+             (the full workspace) can be answered today",
+            base_version
+        ));
+    }
+    let raw = fetch_references(ctx, sql).await?;
+    Ok(ReferencesDelta {
+        heads: raw.heads,
+        local_bookmarks: raw.local_bookmarks,
+        remote_bookmarks: raw.remote_bookmarks,
+        snapshots: raw.snapshots,
+        removed_commits: Vec::new(),
+    })
+}
+
+// Maximum number of ChangesetInfo derivations to drive concurrently when
+// resolving head dates, so a workspace with many heads doesn't open an
+// unbounded number of in-flight derivation requests.
+const HEADS_DATES_CONCURRENCY_LIMIT: usize = 100;
+
 // Cast the raw data into the format the client expects it
 pub(crate) async fn cast_references_data(
     raw_references_data: RawReferencesData,
@@ -164,31 +238,50 @@ pub(crate) async fn cast_references_data(
 ) -> Result<ReferencesData, anyhow::Error> {
     let mut heads: Vec<HgId> = Vec::new();
     let mut bookmarks: HashMap<String, HgId> = HashMap::new();
-    let mut heads_dates: HashMap<HgId, i64> = HashMap::new();
     let mut remote_bookmarks: Vec<RemoteBookmark> = Vec::new();
     let mut snapshots: Vec<HgId> = Vec::new();
 
-    for head in raw_references_data.heads {
-        heads.push(head.commit.into());
-        let bonsai = bonsai_hg_mapping
-            .get_bonsai_from_hg(core_ctx, head.commit)
-            .await?;
-        match bonsai {
-            Some(bonsai) => {
-                let cs_info = repo_derived_data
-                    .derive::<ChangesetInfo>(core_ctx, bonsai.clone())
-                    .await?;
-                let cs_date = cs_info.author_date();
-                heads_dates.insert(head.commit.into(), cs_date.as_chrono().timestamp());
-            }
-            None => {
-                return Err(anyhow!(
-                    "Changeset {} not found in bonsai mapping",
-                    head.commit
-                ));
-            }
-        }
+    let head_ids: Vec<HgChangesetId> = raw_references_data
+        .heads
+        .iter()
+        .map(|head| {
+            heads.push(head.commit.into());
+            head.commit
+        })
+        .collect();
+
+    let bonsai_by_hg: HashMap<HgChangesetId, ChangesetId> = bonsai_hg_mapping
+        .get_many_bonsai_by_hg(core_ctx, head_ids.clone())
+        .await?
+        .into_iter()
+        .collect();
+
+    let missing: Vec<String> = head_ids
+        .iter()
+        .filter(|hg| !bonsai_by_hg.contains_key(hg))
+        .map(|hg| hg.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Changesets {} not found in bonsai mapping",
+            missing.join(", ")
+        ));
     }
+
+    let heads_dates: HashMap<HgId, i64> = stream::iter(head_ids.into_iter().map(|hg| {
+        let bonsai = bonsai_by_hg[&hg];
+        let repo_derived_data = &repo_derived_data;
+        async move {
+            let cs_info = repo_derived_data
+                .derive::<ChangesetInfo>(core_ctx, bonsai)
+                .await?;
+            anyhow::Ok((hg.into(), cs_info.author_date().as_chrono().timestamp()))
+        }
+    }))
+    .buffer_unordered(HEADS_DATES_CONCURRENCY_LIMIT)
+    .try_collect()
+    .await?;
+
     for bookmark in raw_references_data.local_bookmarks {
         bookmarks.insert(bookmark.name().clone(), (*bookmark.commit()).into());
     }
@@ -216,14 +309,58 @@ pub(crate) async fn cast_references_data(
     })
 }
 
+// Returned when a client's `base_version` no longer matches the workspace's
+// current version, meaning another client has updated it in the meantime.
+#[derive(Debug, thiserror::Error)]
+pub enum UpdateReferencesConflict {
+    #[error(
+        "workspace {workspace} in repo {reponame} was concurrently updated: expected version {expected} is no longer current"
+    )]
+    VersionMismatch {
+        reponame: String,
+        workspace: String,
+        expected: u64,
+    },
+}
+
 pub(crate) async fn update_references_data(
     sql: &SqlCommitCloud,
     txn: Transaction,
     cri: Option<&ClientRequestInfo>,
     params: UpdateReferencesParams,
     ctx: &CommitCloudContext,
+    base_version: u64,
 ) -> anyhow::Result<Transaction> {
     let mut txn = txn;
+
+    // Check-and-bump the workspace version with a single `Update` call on
+    // `txn`, the same transaction the rest of this function mutates in: the
+    // `CompareAndSwap` update only matches (and advances) the row while its
+    // stored version is still `base_version`, so a concurrent writer that
+    // already bumped it makes this affect 0 rows instead of racing with a
+    // separate read. This mirrors `rename_all`, where `WorkspaceVersion` is
+    // always updated via its own explicit `Update::<WorkspaceVersion>::update`
+    // call rather than as a side effect of the other tables' updates.
+    let affected_rows;
+    (txn, affected_rows) = Update::<WorkspaceVersion>::update(
+        sql,
+        txn,
+        cri,
+        ctx.clone(),
+        UpdateVersionArgs::CompareAndSwap {
+            expected: base_version,
+        },
+    )
+    .await?;
+    if affected_rows == 0 {
+        return Err(UpdateReferencesConflict::VersionMismatch {
+            reponame: ctx.reponame.clone(),
+            workspace: ctx.workspace.clone(),
+            expected: base_version,
+        }
+        .into());
+    }
+
     txn = update_heads(sql, txn, cri, ctx, params.removed_heads, params.new_heads).await?;
     txn = update_bookmarks(
         sql,
@@ -290,3 +427,27 @@ pub async fn rename_all(
     .await?;
     Ok((txn, affected_rows))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `update_references_data`'s compare-and-swap path and
+    // `fetch_references_since` need a real `SqlCommitCloud` (and the
+    // `CommitCloudContext`/`WorkspaceVersion` types it depends on), which in
+    // turn needs the `sql_construct` in-memory test harness that isn't part
+    // of this checkout, so neither is tested here. What's testable here,
+    // without a database, is the error formatting surfaced on conflict.
+    #[test]
+    fn update_references_conflict_message_names_workspace_and_expected_version() {
+        let err = UpdateReferencesConflict::VersionMismatch {
+            reponame: "repo".to_string(),
+            workspace: "user/test".to_string(),
+            expected: 41,
+        };
+        let message = err.to_string();
+        assert!(message.contains("repo"));
+        assert!(message.contains("user/test"));
+        assert!(message.contains("41"));
+    }
+}
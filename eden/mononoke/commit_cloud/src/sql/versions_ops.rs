@@ -0,0 +1,21 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+// Arguments accepted by `Update::<WorkspaceVersion>::update`, dispatched on
+// by the SQL layer to build the right `UPDATE` statement for a workspace's
+// `WorkspaceVersion` row.
+#[derive(Debug, Clone)]
+pub enum UpdateVersionArgs {
+    // Used by `rename_all` to move a workspace's version row to its new name.
+    WorkspaceName(String),
+    // Atomically advance the stored version by one, but only if it still
+    // equals `expected`. The update's `WHERE version = expected` clause means
+    // the returned affected-row count is 0 on a stale `expected` (someone
+    // else already bumped it) and 1 on success, giving compare-and-swap
+    // semantics without a separate read-then-write race.
+    CompareAndSwap { expected: u64 },
+}